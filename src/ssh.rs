@@ -1,11 +1,20 @@
-use async_ssh;
 use failure::{Error, ResultExt};
-use futures::{self, Future};
+use futures::task::Task;
+use futures::{self, Async, Future, Poll, Stream};
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
 use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
+use thrussh;
+use thrussh::client;
 use thrussh_keys;
-use tokio_core;
-use tokio_io;
+use thrussh_keys::key;
+use tokio;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_io::{self, AsyncRead, AsyncWrite};
 use tokio_timer::Deadline;
 
 /// An established SSH session.
@@ -16,8 +25,50 @@ use tokio_timer::Deadline;
 ///
 /// To execute a command and get its `STDOUT` output, use
 /// [`Session#cmd`](struct.Session.html#method.cmd).
+///
+/// This talks to `thrussh`'s client `Handle` directly rather than through a wrapper crate, so
+/// that it can reach protocol features -- extended-data (stderr), `direct-tcpip`,
+/// `tcpip-forward` -- that a thinner wrapper doesn't expose. The underlying connection is
+/// reference-counted internally, which is what allows
+/// [`Session#forward_local`](struct.Session.html#method.forward_local) and
+/// [`Session#forward_remote`](struct.Session.html#method.forward_remote) to keep opening new
+/// channels on the connection for as long as the forward is active, without holding on to a
+/// borrow of the `Session` itself. All of `Session`'s futures are driven by the ambient `tokio`
+/// runtime.
 pub struct Session {
-    ssh: async_ssh::Session<tokio_core::net::TcpStream>,
+    handle: Arc<Mutex<client::Handle<ClientHandler>>>,
+    dispatch: Arc<Dispatch>,
+}
+
+/// Options controlling how [`Session::connect`](struct.Session.html#method.connect) retries a
+/// connection that has not yet succeeded.
+///
+/// The default is to give each attempt 3 seconds before retrying, and to give up after 120
+/// seconds in total, matching tsunami's original hard-coded behavior.
+pub struct ConnectOptions<'a> {
+    /// How long to wait for a single connection attempt before retrying.
+    ///
+    /// `None` means wait indefinitely for one attempt to succeed or fail.
+    pub attempt_timeout: Option<Duration>,
+    /// The overall budget for retrying, measured from the first attempt.
+    ///
+    /// `None` means keep retrying indefinitely, subject to `still_alive` if it is set.
+    /// Ignored if `still_alive` is set.
+    pub total_timeout: Option<Duration>,
+    /// When set, overrides `total_timeout`: connection attempts continue for as long as this
+    /// returns `true`, no matter how much wall-clock time has elapsed. This is useful for not
+    /// giving up on a slow-booting instance as long as it is still known to be active.
+    pub still_alive: Option<&'a (Fn() -> bool + Send + Sync)>,
+}
+
+impl<'a> Default for ConnectOptions<'a> {
+    fn default() -> Self {
+        ConnectOptions {
+            attempt_timeout: Some(Duration::from_secs(3)),
+            total_timeout: Some(Duration::from_secs(120)),
+            still_alive: None,
+        }
+    }
 }
 
 impl Session {
@@ -25,87 +76,1098 @@ impl Session {
         username: &'a str,
         addr: SocketAddr,
         key: &str,
-        handle: &'a tokio_core::reactor::Handle,
-    ) -> Box<Future<Item = Self, Error = Error> + 'a> {
+        options: ConnectOptions<'a>,
+    ) -> Box<Future<Item = Self, Error = Error> + Send + 'a> {
         // TODO: avoid decoding the key multiple times
         let key = thrussh_keys::decode_secret_key(key, None).unwrap();
 
-        // TODO: instead of max time, keep trying as long as instance is still active
         let start = Instant::now();
+        let ConnectOptions {
+            attempt_timeout,
+            total_timeout,
+            still_alive,
+        } = options;
 
         Box::new(
             futures::future::loop_fn((), move |_| {
-                Deadline::new(
-                    tokio_core::net::TcpStream::connect(&addr, handle),
-                    Instant::now() + Duration::from_secs(3),
-                ).then(move |r| match r {
+                let attempt: Box<Future<Item = TcpStream, Error = Error> + Send> =
+                    match attempt_timeout {
+                        Some(timeout) => Box::new(
+                            Deadline::new(TcpStream::connect(&addr), Instant::now() + timeout)
+                                .map_err(Error::from),
+                        ),
+                        None => Box::new(TcpStream::connect(&addr).map_err(Error::from)),
+                    };
+
+                attempt.then(move |r| match r {
                     Ok(c) => Ok(futures::future::Loop::Break(c)),
-                    Err(_) if start.elapsed() <= Duration::from_secs(120) => {
-                        Ok(futures::future::Loop::Continue(()))
+                    Err(e) => {
+                        let keep_trying = match still_alive {
+                            Some(still_alive) => still_alive(),
+                            None => match total_timeout {
+                                Some(budget) => start.elapsed() <= budget,
+                                None => true,
+                            },
+                        };
+                        if keep_trying {
+                            Ok(futures::future::Loop::Continue(()))
+                        } else {
+                            Err(e.context("failed to connect to ssh port").into())
+                        }
                     }
-                    Err(e) => Err(Error::from(e).context("failed to connect to ssh port")),
-                })
-            }).then(|r| r.context("failed to connect to ssh port"))
-                .map_err(Into::into)
-                .and_then(move |c| {
-                    async_ssh::Session::new(c, &handle)
-                        .map_err(|e| format_err!("{:?}", e))
-                        .context("failed to establish ssh session")
                 })
-                .and_then(move |session| {
-                    session
+            }).and_then(move |stream| {
+                let dispatch = Arc::new(Dispatch::new());
+                let config = Arc::new(client::Config::default());
+                let handler = ClientHandler {
+                    dispatch: Arc::clone(&dispatch),
+                };
+                client::connect_stream(config, stream, handler)
+                    .map_err(|e| format_err!("{:?}", e))
+                    .context("failed to establish ssh session")
+                    .map(move |handle| (handle, dispatch))
+            })
+                .and_then(move |(handle, dispatch)| {
+                    handle
                         .authenticate_key(username, key)
                         .map_err(|e| format_err!("{:?}", e))
                         .then(|r| r.context("failed to authenticate ssh session"))
+                        .map(move |handle| (handle, dispatch))
                 })
                 .map_err(Into::into)
-                .map(|ssh| Session { ssh }),
+                .map(|(handle, dispatch)| Session {
+                    handle: Arc::new(Mutex::new(handle)),
+                    dispatch,
+                }),
         )
     }
 
-    /// Issue the given command and return the command's raw standard output.
-    pub fn cmd_raw<'a>(&mut self, cmd: &'a str) -> Box<Future<Item = Vec<u8>, Error = Error> + 'a> {
-        // TODO: check channel.exit_status()
-        // TODO: return stderr as well?
+    /// Open a new channel on the underlying connection and register it with `self.dispatch`,
+    /// returning the channel id together with the state that `ClientHandler` will keep updated
+    /// for it.
+    fn open_channel(
+        &self,
+    ) -> Box<Future<Item = (thrussh::ChannelId, Arc<Mutex<ChannelState>>), Error = Error> + Send>
+    {
+        let dispatch = Arc::clone(&self.dispatch);
         Box::new(
-            self.ssh
-                .open_exec(cmd)
+            self.handle
+                .lock()
+                .unwrap()
+                .channel_open_session()
                 .map_err(|e| format_err!("{:?}", e))
-                .then(move |e| {
-                    e.map_err(|e| format_err!("{:?}", e))
-                        .context(format!("failed to execute command '{}'", cmd))
+                .context("failed to open ssh channel")
+                .from_err()
+                .map(move |id| {
+                    let state = dispatch.register(id);
+                    (id, state)
+                }),
+        )
+    }
+
+    /// Issue the given command and return its raw standard output, standard error, and exit
+    /// status, without checking whether the command succeeded.
+    pub fn cmd_raw_status(
+        &self,
+        cmd: impl Into<String>,
+    ) -> Box<Future<Item = CmdResult, Error = Error> + Send> {
+        let cmd = cmd.into();
+        let cmd_for_exec_err = cmd.clone();
+        let handle = Arc::clone(&self.handle);
+        let dispatch = Arc::clone(&self.dispatch);
+        Box::new(self.open_channel().and_then(move |(id, state)| {
+            handle
+                .lock()
+                .unwrap()
+                .exec(id, &cmd)
+                .map_err(|e| format_err!("{:?}", e))
+                .context(format!("failed to execute command '{}'", cmd_for_exec_err))
+                .from_err()
+                .and_then(move |_| wait_for_channel_done(state))
+                .map(move |state| {
+                    dispatch.forget(id);
+                    let s = state.lock().unwrap();
+                    CmdResult {
+                        stdout: s.stdout.clone(),
+                        stderr: s.stderr.clone(),
+                        exit_status: s.exit_status,
+                        exit_signal: s.exit_signal.clone(),
+                    }
                 })
+        }))
+    }
+
+    /// Issue the given command, and fail with a descriptive error if it did not exit
+    /// successfully.
+    pub fn cmd_status(
+        &self,
+        cmd: impl Into<String>,
+    ) -> Box<Future<Item = CmdResult, Error = Error> + Send> {
+        let cmd = cmd.into();
+        let cmd_for_err = cmd.clone();
+        Box::new(self.cmd_raw_status(cmd).and_then(move |res| {
+            match res.exit_status {
+                Some(0) => Ok(res),
+                Some(code) => Err(format_err!(
+                    "command '{}' exited with status {}: {}",
+                    cmd_for_err,
+                    code,
+                    String::from_utf8_lossy(&res.stderr)
+                )),
+                None => Err(format_err!(
+                    "command '{}' was terminated by signal {}: {}",
+                    cmd_for_err,
+                    res.exit_signal.as_ref().map(String::as_str).unwrap_or("unknown"),
+                    String::from_utf8_lossy(&res.stderr)
+                )),
+            }
+        }))
+    }
+
+    /// Issue the given command and return the command's raw standard output, without checking
+    /// whether the command succeeded.
+    pub fn cmd_raw(&self, cmd: impl Into<String>) -> Box<Future<Item = Vec<u8>, Error = Error> + Send> {
+        Box::new(self.cmd_raw_status(cmd).map(|res| res.stdout))
+    }
+
+    /// Issue the given command and return the command's standard output, failing if the
+    /// command did not exit successfully.
+    pub fn cmd(&self, cmd: impl Into<String>) -> Box<Future<Item = String, Error = Error> + Send> {
+        Box::new(self.cmd_status(cmd).and_then(|res| {
+            String::from_utf8(res.stdout)
+                .context("invalid utf-8 in command output")
                 .map_err(Into::into)
-                .and_then(move |c| {
-                    tokio_io::io::read_to_end(c, Vec::new()).then(move |r| {
-                        r.context(format!("failed to read stdout of command '{}'", cmd))
-                    })
+        }))
+    }
+
+    /// Run every command in `cmds` over the same underlying SSH session, returning each
+    /// command's [`CmdResult`](struct.CmdResult.html) in the same order as given.
+    ///
+    /// The commands run concurrently: each opens its own channel, so they overlap in
+    /// wall-clock time rather than waiting for one another to finish. Opening a channel only
+    /// needs the session's internal lock for the instant it takes to issue the request, so
+    /// channels cannot corrupt one another's state the way naively driving several channels
+    /// over an unsynchronized connection would -- but reading each channel's output is not
+    /// otherwise coordinated with the others, so this is not a way to impose ordering between
+    /// `cmds`.
+    ///
+    /// Each command's exit status is reported through its own `CmdResult` rather than failing
+    /// the whole batch: one command exiting non-zero does not keep the others' results from
+    /// being returned, so the caller can inspect every `exit_status` itself.
+    pub fn execute_all(
+        &self,
+        cmds: Vec<String>,
+    ) -> Box<Future<Item = Vec<CmdResult>, Error = Error> + Send> {
+        Box::new(futures::future::join_all(
+            cmds.into_iter().map(move |cmd| self.cmd_raw_status(cmd)),
+        ))
+    }
+
+    /// Open a new channel and start the `sftp` subsystem on it.
+    fn sftp_channel(&self) -> Box<Future<Item = RawChannel, Error = Error> + Send> {
+        let handle = Arc::clone(&self.handle);
+        Box::new(self.open_channel().and_then(move |(id, state)| {
+            handle
+                .lock()
+                .unwrap()
+                .request_subsystem(id, "sftp")
+                .map_err(|e| format_err!("{:?}", e))
+                .context("failed to start sftp subsystem")
+                .from_err()
+                .map(move |_| RawChannel {
+                    handle,
+                    id,
+                    state,
+                    read_pos: 0,
+                })
+        }))
+    }
+
+    /// Write `contents` to `remote_path` on the remote host, creating or truncating the file as
+    /// necessary.
+    pub fn write_file<'a>(
+        &self,
+        contents: Vec<u8>,
+        remote_path: &'a str,
+    ) -> Box<Future<Item = (), Error = Error> + Send + 'a> {
+        let remote_path = remote_path.to_string();
+        Box::new(self.sftp_channel().and_then(move |channel| {
+            sftp::init(channel)
+                .and_then(move |channel| sftp::open(channel, &remote_path, sftp::SSH_FXF_WRITE | sftp::SSH_FXF_CREAT | sftp::SSH_FXF_TRUNC))
+                .and_then(move |(channel, handle)| sftp::write_all(channel, handle, contents))
+                .and_then(|(channel, handle)| sftp::close(channel, handle))
+                .map(|_channel| ())
+        }))
+    }
+
+    /// Read the full contents of `remote_path` on the remote host.
+    pub fn read_file<'a>(
+        &self,
+        remote_path: &'a str,
+    ) -> Box<Future<Item = Vec<u8>, Error = Error> + Send + 'a> {
+        let remote_path = remote_path.to_string();
+        Box::new(self.sftp_channel().and_then(move |channel| {
+            sftp::init(channel)
+                .and_then(move |channel| sftp::open(channel, &remote_path, sftp::SSH_FXF_READ))
+                .and_then(|(channel, handle)| sftp::read_all(channel, handle))
+                .and_then(|(channel, handle, contents)| {
+                    sftp::close(channel, handle).map(move |channel| (channel, contents))
                 })
-                .map(|(_, b)| b)
-                .map_err(Into::into),
+                .map(|(_channel, contents)| contents)
+        }))
+    }
+
+    /// Upload the file at `local` to `remote_path` on the remote host.
+    pub fn upload<'a>(
+        &self,
+        local: &'a Path,
+        remote_path: &'a str,
+    ) -> Box<Future<Item = (), Error = Error> + Send + 'a> {
+        Box::new(
+            tokio::fs::read(local)
+                .context(format!("failed to read local file '{}'", local.display()))
+                .from_err()
+                .and_then(move |contents| self.write_file(contents, remote_path)),
         )
     }
 
-    /// Issue the given command and return the command's standard output.
-    pub fn cmd<'a>(&mut self, cmd: &'a str) -> Box<Future<Item = String, Error = Error> + 'a> {
-        Box::new(self.cmd_raw(cmd).and_then(|bytes| {
-            String::from_utf8(bytes)
-                .context("invalid utf-8 in command output")
-                .map_err(Into::into)
+    /// Download `remote_path` on the remote host to the local file at `local`.
+    pub fn download<'a>(
+        &self,
+        remote_path: &'a str,
+        local: &'a Path,
+    ) -> Box<Future<Item = (), Error = Error> + Send + 'a> {
+        Box::new(self.read_file(remote_path).and_then(move |contents| {
+            tokio::fs::write(local, contents)
+                .context(format!("failed to write local file '{}'", local.display()))
+                .from_err()
         }))
     }
+
+    /// Forward connections to `local_addr` to `remote_host:remote_port` as seen from the
+    /// remote host.
+    ///
+    /// Opens a `direct-tcpip` channel for every connection accepted on `local_addr` and splices
+    /// it to the accepted connection, so that e.g. a database or metrics endpoint that is only
+    /// bound to `localhost` on the remote host can be reached locally without exposing it
+    /// publicly. The returned [`Forward`](struct.Forward.html) can be used to stop forwarding.
+    pub fn forward_local(
+        &self,
+        local_addr: SocketAddr,
+        remote_host: &str,
+        remote_port: u16,
+    ) -> Box<Future<Item = Forward, Error = Error> + Send> {
+        let handle = Arc::clone(&self.handle);
+        let dispatch = Arc::clone(&self.dispatch);
+        let remote_host = remote_host.to_string();
+
+        Box::new(
+            futures::future::result(
+                TcpListener::bind(&local_addr)
+                    .context(format!("failed to bind local address {}", local_addr)),
+            ).from_err()
+                .map(move |listener| {
+                    let state = Arc::new(ForwardState::new());
+                    let accepting = Arc::clone(&state);
+                    let returned_state = Arc::clone(&state);
+
+                    let accept_loop = listener
+                        .incoming()
+                        .map_err(Error::from)
+                        .take_while(move |_| Ok(!accepting.stopping()))
+                        .for_each(move |stream| {
+                            let handle = Arc::clone(&handle);
+                            let dispatch = Arc::clone(&dispatch);
+                            let remote_host = remote_host.clone();
+                            let state = Arc::clone(&state);
+                            state.begin();
+
+                            let originator = stream
+                                .peer_addr()
+                                .unwrap_or_else(|_| ([0, 0, 0, 0], 0).into());
+                            let open = handle.lock().unwrap().channel_open_direct_tcpip(
+                                &remote_host,
+                                u32::from(remote_port),
+                                &originator.ip().to_string(),
+                                u32::from(originator.port()),
+                            );
+                            let copy = open
+                                .map_err(|e| format_err!("{:?}", e))
+                                .context(format!(
+                                    "failed to open direct-tcpip channel to {}:{}",
+                                    remote_host, remote_port
+                                ))
+                                .from_err()
+                                .map(move |id| {
+                                    let channel_state = dispatch.register(id);
+                                    RawChannel {
+                                        handle,
+                                        id,
+                                        state: channel_state,
+                                        read_pos: 0,
+                                    }
+                                })
+                                .and_then(|channel| {
+                                    let (cread, cwrite) = channel.split();
+                                    let (lread, lwrite) = stream.split();
+                                    tokio_io::io::copy(cread, lwrite)
+                                        .join(tokio_io::io::copy(lread, cwrite))
+                                        .map(|_| ())
+                                })
+                                .then(move |r: Result<(), Error>| {
+                                    state.end();
+                                    r
+                                });
+
+                            tokio::spawn(copy.map_err(|e| {
+                                warn!("forwarded connection failed: {}", e);
+                            }));
+                            Ok(())
+                        });
+
+                    spawn_forward(accept_loop, returned_state)
+                }),
+        )
+    }
+
+    /// Forward connections made to `remote_port` on the remote host to `local_target`.
+    ///
+    /// Issues a `tcpip-forward` global request for `remote_port`, then for every channel the
+    /// remote end subsequently opens back to us (reported via
+    /// `ClientHandler::server_channel_open_forwarded_tcpip` and queued in `Dispatch`), connects
+    /// to `local_target` and splices the two together. The returned
+    /// [`Forward`](struct.Forward.html) can be used to stop forwarding; this does not ask the
+    /// remote host to cancel the forward (there is no corresponding "undo" global request
+    /// issued here), it only stops accepting new forwarded connections locally.
+    pub fn forward_remote(
+        &self,
+        remote_port: u16,
+        local_target: SocketAddr,
+    ) -> Box<Future<Item = Forward, Error = Error> + Send> {
+        let handle = Arc::clone(&self.handle);
+        let dispatch = Arc::clone(&self.dispatch);
+
+        Box::new(
+            handle
+                .lock()
+                .unwrap()
+                .tcpip_forward("", u32::from(remote_port))
+                .map_err(|e| format_err!("{:?}", e))
+                .context(format!(
+                    "failed to request remote forwarding of port {}",
+                    remote_port
+                ))
+                .from_err()
+                .and_then(move |accepted| {
+                    if !accepted {
+                        return Err(format_err!(
+                            "remote host refused to forward port {}",
+                            remote_port
+                        ));
+                    }
+
+                    let state = Arc::new(ForwardState::new());
+                    let accepting = Arc::clone(&state);
+                    let returned_state = Arc::clone(&state);
+
+                    let accept_loop = forwarded_channels(dispatch, handle)
+                        .take_while(move |_| Ok(!accepting.stopping()))
+                        .for_each(move |channel| {
+                            let state = Arc::clone(&state);
+                            state.begin();
+
+                            let copy = TcpStream::connect(&local_target)
+                                .context(format!(
+                                    "failed to connect to local forwarding target {}",
+                                    local_target
+                                ))
+                                .from_err()
+                                .and_then(|stream| {
+                                    let (cread, cwrite) = channel.split();
+                                    let (lread, lwrite) = stream.split();
+                                    tokio_io::io::copy(cread, lwrite)
+                                        .join(tokio_io::io::copy(lread, cwrite))
+                                        .map(|_| ())
+                                })
+                                .then(move |r: Result<(), Error>| {
+                                    state.end();
+                                    r
+                                });
+
+                            tokio::spawn(copy.map_err(|e| {
+                                warn!("forwarded connection failed: {}", e);
+                            }));
+                            Ok(())
+                        });
+
+                    Ok(spawn_forward(accept_loop, returned_state))
+                }),
+        )
+    }
+}
+
+/// Wait until `state`'s channel has seen both EOF and close, then hand back the (still locked
+/// behind its `Mutex`) state for the caller to read out.
+fn wait_for_channel_done(
+    state: Arc<Mutex<ChannelState>>,
+) -> Box<Future<Item = Arc<Mutex<ChannelState>>, Error = Error> + Send> {
+    let for_poll = Arc::clone(&state);
+    Box::new(
+        futures::future::poll_fn(move || {
+            let mut s = for_poll.lock().unwrap();
+            if s.eof && s.closed {
+                Ok(Async::Ready(()))
+            } else {
+                s.waiting = Some(futures::task::current());
+                Ok(Async::NotReady)
+            }
+        }).map(move |()| state),
+    )
+}
+
+/// Per-channel buffered state, updated by [`ClientHandler`]'s event callbacks as data arrives
+/// on the underlying connection, and polled by whichever `RawChannel` or command future owns
+/// that channel's lifetime.
+struct ChannelState {
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+    exit_status: Option<i32>,
+    exit_signal: Option<String>,
+    eof: bool,
+    closed: bool,
+    waiting: Option<Task>,
 }
 
-use std::ops::{Deref, DerefMut};
-impl Deref for Session {
-    type Target = async_ssh::Session<tokio_core::net::TcpStream>;
-    fn deref(&self) -> &Self::Target {
-        &self.ssh
+impl ChannelState {
+    fn new() -> Self {
+        ChannelState {
+            stdout: Vec::new(),
+            stderr: Vec::new(),
+            exit_status: None,
+            exit_signal: None,
+            eof: false,
+            closed: false,
+            waiting: None,
+        }
+    }
+
+    fn wake(&mut self) {
+        if let Some(task) = self.waiting.take() {
+            task.notify();
+        }
     }
 }
 
-impl DerefMut for Session {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.ssh
+/// Shared registry of channel state, updated by [`ClientHandler`]'s event callbacks and
+/// consumed by `Session`'s public methods.
+struct Dispatch {
+    channels: Mutex<HashMap<thrussh::ChannelId, Arc<Mutex<ChannelState>>>>,
+    /// Channels the remote end has opened on us via a `forwarded-tcpip` channel open, queued
+    /// here by `ClientHandler::server_channel_open_forwarded_tcpip` for `forward_remote`'s
+    /// accept loop to pick up.
+    forwarded: Mutex<Vec<thrussh::ChannelId>>,
+    forward_waiting: Mutex<Option<Task>>,
+}
+
+impl Dispatch {
+    fn new() -> Self {
+        Dispatch {
+            channels: Mutex::new(HashMap::new()),
+            forwarded: Mutex::new(Vec::new()),
+            forward_waiting: Mutex::new(None),
+        }
+    }
+
+    fn register(&self, id: thrussh::ChannelId) -> Arc<Mutex<ChannelState>> {
+        let state = Arc::new(Mutex::new(ChannelState::new()));
+        self.channels.lock().unwrap().insert(id, Arc::clone(&state));
+        state
+    }
+
+    fn with_channel(&self, id: thrussh::ChannelId, f: impl FnOnce(&mut ChannelState)) {
+        if let Some(state) = self.channels.lock().unwrap().get(&id) {
+            f(&mut state.lock().unwrap());
+        }
+    }
+
+    fn forget(&self, id: thrussh::ChannelId) {
+        self.channels.lock().unwrap().remove(&id);
+    }
+
+    fn channel_state(&self, id: thrussh::ChannelId) -> Option<Arc<Mutex<ChannelState>>> {
+        self.channels.lock().unwrap().get(&id).map(Arc::clone)
+    }
+
+    /// Called by `ClientHandler` when the remote end opens a `forwarded-tcpip` channel back to
+    /// us in response to an earlier `tcpip-forward` request.
+    fn push_forwarded(&self, id: thrussh::ChannelId) {
+        self.register(id);
+        self.forwarded.lock().unwrap().push(id);
+        if let Some(task) = self.forward_waiting.lock().unwrap().take() {
+            task.notify();
+        }
+    }
+}
+
+/// An infinite stream of channels the remote end has opened back to us via `forwarded-tcpip`,
+/// backing [`Session::forward_remote`](struct.Session.html#method.forward_remote).
+fn forwarded_channels(
+    dispatch: Arc<Dispatch>,
+    handle: Arc<Mutex<client::Handle<ClientHandler>>>,
+) -> impl Stream<Item = RawChannel, Error = Error> {
+    futures::stream::poll_fn(move || {
+        let id = dispatch.forwarded.lock().unwrap().pop();
+        match id {
+            Some(id) => {
+                let state = dispatch
+                    .channel_state(id)
+                    .expect("push_forwarded always registers the channel before queueing it");
+                Ok(Async::Ready(Some(RawChannel {
+                    handle: Arc::clone(&handle),
+                    id,
+                    state,
+                    read_pos: 0,
+                })))
+            }
+            None => {
+                *dispatch.forward_waiting.lock().unwrap() = Some(futures::task::current());
+                Ok(Async::NotReady)
+            }
+        }
+    })
+}
+
+/// Demultiplexes events from the underlying `thrussh` connection into each open channel's
+/// [`ChannelState`], via the shared [`Dispatch`].
+///
+/// Host keys are accepted unconditionally: tsunami connects to freshly booted cloud instances
+/// whose host keys are ephemeral and unknown in advance, so there is nothing meaningful to pin
+/// here -- the SSH key pair provisioned at instance creation is what actually authenticates the
+/// connection.
+#[derive(Clone)]
+struct ClientHandler {
+    dispatch: Arc<Dispatch>,
+}
+
+impl client::Handler for ClientHandler {
+    type Error = Error;
+    type FutureUnit = Box<Future<Item = (Self, client::Session), Error = Self::Error> + Send>;
+    type FutureBool = Box<Future<Item = (Self, bool), Error = Self::Error> + Send>;
+
+    fn check_server_key(self, _server_public_key: &key::PublicKey) -> Self::FutureBool {
+        Box::new(futures::finished((self, true)))
+    }
+
+    fn data(self, channel: thrussh::ChannelId, data: &[u8], session: client::Session) -> Self::FutureUnit {
+        self.dispatch.with_channel(channel, |s| {
+            s.stdout.extend_from_slice(data);
+            s.wake();
+        });
+        Box::new(futures::finished((self, session)))
+    }
+
+    fn extended_data(
+        self,
+        channel: thrussh::ChannelId,
+        ext: u32,
+        data: &[u8],
+        session: client::Session,
+    ) -> Self::FutureUnit {
+        if ext == 1 {
+            self.dispatch.with_channel(channel, |s| {
+                s.stderr.extend_from_slice(data);
+                s.wake();
+            });
+        }
+        Box::new(futures::finished((self, session)))
+    }
+
+    fn channel_eof(self, channel: thrussh::ChannelId, session: client::Session) -> Self::FutureUnit {
+        self.dispatch.with_channel(channel, |s| {
+            s.eof = true;
+            s.wake();
+        });
+        Box::new(futures::finished((self, session)))
+    }
+
+    fn channel_close(self, channel: thrussh::ChannelId, session: client::Session) -> Self::FutureUnit {
+        self.dispatch.with_channel(channel, |s| {
+            s.closed = true;
+            s.wake();
+        });
+        Box::new(futures::finished((self, session)))
+    }
+
+    fn exit_status(
+        self,
+        channel: thrussh::ChannelId,
+        exit_status: u32,
+        session: client::Session,
+    ) -> Self::FutureUnit {
+        self.dispatch.with_channel(channel, |s| {
+            s.exit_status = Some(exit_status as i32);
+            s.wake();
+        });
+        Box::new(futures::finished((self, session)))
+    }
+
+    fn exit_signal(
+        self,
+        channel: thrussh::ChannelId,
+        signal_name: thrussh::Sig,
+        _core_dumped: bool,
+        _error_message: String,
+        _lang_tag: String,
+        session: client::Session,
+    ) -> Self::FutureUnit {
+        self.dispatch.with_channel(channel, |s| {
+            s.exit_signal = Some(format!("{:?}", signal_name));
+            s.wake();
+        });
+        Box::new(futures::finished((self, session)))
+    }
+
+    fn server_channel_open_forwarded_tcpip(
+        self,
+        channel: thrussh::ChannelId,
+        _connected_address: &str,
+        _connected_port: u32,
+        _originator_address: &str,
+        _originator_port: u32,
+        session: client::Session,
+    ) -> Self::FutureUnit {
+        self.dispatch.push_forwarded(channel);
+        Box::new(futures::finished((self, session)))
+    }
+}
+
+/// A single multiplexed SSH channel, read and written as a plain byte stream.
+///
+/// Reads drain `ChannelState::stdout` as `ClientHandler::data` appends to it; writes are handed
+/// off to the underlying connection via `Handle::data` and are not itself backpressured beyond
+/// what `thrussh` already queues internally.
+struct RawChannel {
+    handle: Arc<Mutex<client::Handle<ClientHandler>>>,
+    id: thrussh::ChannelId,
+    state: Arc<Mutex<ChannelState>>,
+    read_pos: usize,
+}
+
+impl Read for RawChannel {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        if self.read_pos < state.stdout.len() {
+            let n = ::std::cmp::min(buf.len(), state.stdout.len() - self.read_pos);
+            buf[..n].copy_from_slice(&state.stdout[self.read_pos..self.read_pos + n]);
+            self.read_pos += n;
+            Ok(n)
+        } else if state.eof || state.closed {
+            Ok(0)
+        } else {
+            state.waiting = Some(futures::task::current());
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+    }
+}
+
+impl AsyncRead for RawChannel {}
+
+impl Write for RawChannel {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let fut = self.handle.lock().unwrap().data(self.id, buf.to_vec());
+        tokio::spawn(fut.map_err(|e| warn!("failed to write to ssh channel: {:?}", e)));
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsyncWrite for RawChannel {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        let fut = self.handle.lock().unwrap().eof(self.id);
+        tokio::spawn(fut.map_err(|e| warn!("failed to send eof on ssh channel: {:?}", e)));
+        Ok(Async::Ready(()))
+    }
+}
+
+/// Shared bookkeeping between a running forward's accept loop and its [`Forward`] handle:
+/// whether the caller has asked us to stop accepting new connections, how many forwarded
+/// connections are still being copied, and who to wake up once both are done.
+struct ForwardState {
+    stopping: AtomicBool,
+    outstanding: AtomicUsize,
+    waiting: Mutex<Option<Task>>,
+}
+
+impl ForwardState {
+    fn new() -> Self {
+        ForwardState {
+            stopping: AtomicBool::new(false),
+            outstanding: AtomicUsize::new(0),
+            waiting: Mutex::new(None),
+        }
+    }
+
+    fn stopping(&self) -> bool {
+        self.stopping.load(Ordering::SeqCst)
+    }
+
+    fn stop(&self) {
+        self.stopping.store(true, Ordering::SeqCst);
+        self.notify_if_done();
+    }
+
+    fn begin(&self) {
+        self.outstanding.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn end(&self) {
+        self.outstanding.fetch_sub(1, Ordering::SeqCst);
+        self.notify_if_done();
+    }
+
+    fn done(&self) -> bool {
+        self.stopping() && self.outstanding.load(Ordering::SeqCst) == 0
+    }
+
+    fn notify_if_done(&self) {
+        if self.done() {
+            if let Some(task) = self.waiting.lock().unwrap().take() {
+                task.notify();
+            }
+        }
+    }
+}
+
+/// Spawns `accept_loop` on the ambient `tokio` runtime and returns a [`Forward`] that lets the
+/// caller stop it and wait for any connections it is still forwarding to finish.
+fn spawn_forward<F>(accept_loop: F, state: Arc<ForwardState>) -> Forward
+where
+    F: Future<Item = (), Error = Error> + Send + 'static,
+{
+    tokio::spawn(accept_loop.then(move |r| {
+        if let Err(e) = r {
+            warn!("port forward accept loop failed: {}", e);
+        }
+        state.stop();
+        Ok(())
+    }));
+
+    Forward { state }
+}
+
+/// A handle to a port forward established by
+/// [`Session::forward_local`](struct.Session.html#method.forward_local).
+///
+/// Dropping a `Forward` does not stop it; call [`Forward::stop`](#method.stop) to stop
+/// accepting new connections and wait for outstanding ones to finish.
+pub struct Forward {
+    state: Arc<ForwardState>,
+}
+
+impl Forward {
+    /// Stop accepting new connections on this forward, and return a future that resolves once
+    /// every connection that was already being forwarded has finished.
+    pub fn stop(self) -> impl Future<Item = (), Error = Error> {
+        self.state.stop();
+        futures::future::poll_fn(move || {
+            // Register ourselves to be woken *before* re-checking `done()`: if we checked
+            // first, a concurrent `end()` could drop `outstanding` to zero and call
+            // `notify_if_done()` in the window between our check and storing the waker,
+            // finding nothing to notify -- and we'd then park forever having missed it.
+            *self.state.waiting.lock().unwrap() = Some(futures::task::current());
+            if self.state.done() {
+                Ok(futures::Async::Ready(()))
+            } else {
+                Ok(futures::Async::NotReady)
+            }
+        })
+    }
+}
+
+/// The outcome of running a command on a remote host via [`Session::cmd_raw_status`] or
+/// [`Session::cmd_status`].
+pub struct CmdResult {
+    /// The bytes the command wrote to its standard output.
+    pub stdout: Vec<u8>,
+    /// The bytes the command wrote to its standard error.
+    pub stderr: Vec<u8>,
+    /// The exit code the command terminated with, if it exited normally.
+    pub exit_status: Option<i32>,
+    /// The name of the signal that terminated the command, if it did not exit normally.
+    pub exit_signal: Option<String>,
+}
+
+/// A minimal SFTP version 3 client, just enough of the protocol (`RFC draft-ietf-secsh-filexfer
+/// -02`) to open, read, write, and close a single file over an already-started `sftp`
+/// subsystem channel.
+///
+/// Every request here uses the fixed request id `0`: `Session` only ever has one SFTP request
+/// in flight per channel (it waits for each response before sending the next), so there is
+/// nothing to demultiplex and a real request-id table would be dead weight.
+mod sftp {
+    use super::RawChannel;
+    use failure::{Error, ResultExt};
+    use futures::{Future, Stream};
+    use tokio_io;
+
+    pub const SSH_FXF_READ: u32 = 0x01;
+    pub const SSH_FXF_WRITE: u32 = 0x02;
+    pub const SSH_FXF_CREAT: u32 = 0x08;
+    pub const SSH_FXF_TRUNC: u32 = 0x10;
+
+    const SSH_FXP_INIT: u8 = 1;
+    const SSH_FXP_OPEN: u8 = 3;
+    const SSH_FXP_CLOSE: u8 = 4;
+    const SSH_FXP_READ: u8 = 5;
+    const SSH_FXP_WRITE: u8 = 6;
+    const SSH_FXP_STATUS: u8 = 101;
+    const SSH_FXP_HANDLE: u8 = 102;
+    const SSH_FXP_DATA: u8 = 103;
+    const SSH_FXP_VERSION: u8 = 2;
+
+    const SSH_FX_OK: u32 = 0;
+    const SSH_FX_EOF: u32 = 1;
+
+    /// A file handle as returned by the server's `SSH_FXP_HANDLE` response to `SSH_FXP_OPEN`.
+    /// Opaque to the client: it is just handed back verbatim in subsequent requests.
+    pub struct Handle(Vec<u8>);
+
+    fn put_u32(buf: &mut Vec<u8>, v: u32) {
+        buf.extend_from_slice(&v.to_be_bytes());
+    }
+
+    fn put_u64(buf: &mut Vec<u8>, v: u64) {
+        buf.extend_from_slice(&v.to_be_bytes());
+    }
+
+    fn put_bytes(buf: &mut Vec<u8>, s: &[u8]) {
+        put_u32(buf, s.len() as u32);
+        buf.extend_from_slice(s);
+    }
+
+    fn get_u32(buf: &[u8], pos: &mut usize) -> u32 {
+        let v = u32::from_be_bytes([buf[*pos], buf[*pos + 1], buf[*pos + 2], buf[*pos + 3]]);
+        *pos += 4;
+        v
+    }
+
+    fn get_bytes(buf: &[u8], pos: &mut usize) -> Vec<u8> {
+        let len = get_u32(buf, pos) as usize;
+        let v = buf[*pos..*pos + len].to_vec();
+        *pos += len;
+        v
+    }
+
+    /// Send a request packet (length-prefixed type + body) and read back the response packet,
+    /// returning the channel (so the next request can be sent), the response's type byte, and
+    /// its body.
+    fn roundtrip(
+        channel: RawChannel,
+        packet_type: u8,
+        mut body: Vec<u8>,
+    ) -> Box<Future<Item = (RawChannel, u8, Vec<u8>), Error = Error> + Send> {
+        let mut packet = Vec::with_capacity(5 + body.len());
+        put_u32(&mut packet, 1 + body.len() as u32);
+        packet.push(packet_type);
+        packet.append(&mut body);
+
+        Box::new(
+            tokio_io::io::write_all(channel, packet)
+                .context("failed to write sftp request")
+                .from_err()
+                .and_then(|(channel, _)| {
+                    tokio_io::io::read_exact(channel, [0u8; 4])
+                        .context("failed to read sftp response length")
+                        .from_err()
+                })
+                .and_then(|(channel, len_buf)| {
+                    let len = u32::from_be_bytes(len_buf) as usize;
+                    tokio_io::io::read_exact(channel, vec![0u8; len])
+                        .context("failed to read sftp response body")
+                        .from_err()
+                })
+                .map(|(channel, mut body)| {
+                    let packet_type = body.remove(0);
+                    (channel, packet_type, body)
+                }),
+        )
+    }
+
+    /// Check that a `SSH_FXP_STATUS` response body reports success, turning anything else into
+    /// a descriptive error.
+    fn check_status(what: &str, body: Vec<u8>) -> Result<(), Error> {
+        let mut pos = 0;
+        let code = get_u32(&body, &mut pos);
+        if code == SSH_FX_OK {
+            Ok(())
+        } else {
+            let message = get_bytes(&body, &mut pos);
+            Err(format_err!(
+                "sftp {} failed with status {}: {}",
+                what,
+                code,
+                String::from_utf8_lossy(&message)
+            ))
+        }
+    }
+
+    /// Send `SSH_FXP_INIT` and wait for the server's `SSH_FXP_VERSION`, confirming the channel
+    /// actually speaks SFTP before we try to use it.
+    pub fn init(channel: RawChannel) -> Box<Future<Item = RawChannel, Error = Error> + Send> {
+        let mut body = Vec::new();
+        put_u32(&mut body, 3); // we speak version 3
+        Box::new(roundtrip(channel, SSH_FXP_INIT, body).and_then(|(channel, ty, _body)| {
+            if ty == SSH_FXP_VERSION {
+                Ok(channel)
+            } else {
+                Err(format_err!(
+                    "expected sftp SSH_FXP_VERSION, got packet type {}",
+                    ty
+                ))
+            }
+        }))
+    }
+
+    pub fn open(
+        channel: RawChannel,
+        path: &str,
+        pflags: u32,
+    ) -> Box<Future<Item = (RawChannel, Handle), Error = Error> + Send> {
+        let mut body = Vec::new();
+        put_u32(&mut body, 0); // request id
+        put_bytes(&mut body, path.as_bytes());
+        put_u32(&mut body, pflags);
+        put_u32(&mut body, 0); // ATTRS.flags: no optional attributes present
+        let path = path.to_string();
+        Box::new(
+            roundtrip(channel, SSH_FXP_OPEN, body).and_then(move |(channel, ty, mut body)| {
+                if ty == SSH_FXP_HANDLE {
+                    let mut pos = 0;
+                    let _request_id = get_u32(&body, &mut pos);
+                    let handle = get_bytes(&body, &mut pos);
+                    Ok((channel, Handle(handle)))
+                } else {
+                    // SSH_FXP_STATUS: a server never answers SSH_FXP_OPEN with a *successful*
+                    // status (success is always a SSH_FXP_HANDLE instead), so reaching here is
+                    // always a failure.
+                    let mut pos = 0;
+                    let _request_id = get_u32(&body, &mut pos);
+                    let code = get_u32(&body, &mut pos);
+                    let message = get_bytes(&body, &mut pos);
+                    Err(format_err!(
+                        "failed to open remote file '{}': sftp status {}: {}",
+                        path,
+                        code,
+                        String::from_utf8_lossy(&message)
+                    ))
+                }
+            }),
+        )
+    }
+
+    pub fn write_all(
+        channel: RawChannel,
+        handle: Handle,
+        contents: Vec<u8>,
+    ) -> Box<Future<Item = (RawChannel, Handle), Error = Error> + Send> {
+        // A conservative chunk size comfortably under both SSH's default max packet size and
+        // common sftp-server packet limits.
+        const CHUNK: usize = 32 * 1024;
+        let chunks: Vec<Vec<u8>> = contents
+            .chunks(CHUNK)
+            .map(|c| c.to_vec())
+            .collect();
+
+        Box::new(futures::stream::iter_ok(chunks).fold(
+            (channel, handle, 0u64),
+            |(channel, handle, offset), chunk| {
+                let len = chunk.len() as u64;
+                let mut body = Vec::new();
+                put_u32(&mut body, 0); // request id
+                put_bytes(&mut body, &handle.0);
+                put_u64(&mut body, offset);
+                put_bytes(&mut body, &chunk);
+                roundtrip(channel, SSH_FXP_WRITE, body).and_then(move |(channel, ty, mut body)| {
+                    if ty != SSH_FXP_STATUS {
+                        return Err(format_err!("expected sftp SSH_FXP_STATUS, got packet type {}", ty));
+                    }
+                    body.drain(..4);
+                    check_status("write", body)?;
+                    Ok((channel, handle, offset + len))
+                })
+            },
+        ).map(|(channel, handle, _offset)| (channel, handle)))
+    }
+
+    pub fn read_all(
+        channel: RawChannel,
+        handle: Handle,
+    ) -> Box<Future<Item = (RawChannel, Handle, Vec<u8>), Error = Error> + Send> {
+        // Same chunk size rationale as `write_all`.
+        const CHUNK: u32 = 32 * 1024;
+        Box::new(futures::future::loop_fn(
+            (channel, handle, 0u64, Vec::new()),
+            |(channel, handle, offset, mut contents)| {
+                let mut body = Vec::new();
+                put_u32(&mut body, 0); // request id
+                put_bytes(&mut body, &handle.0);
+                put_u64(&mut body, offset);
+                put_u32(&mut body, CHUNK);
+                roundtrip(channel, SSH_FXP_READ, body).and_then(move |(channel, ty, mut body)| {
+                    let mut pos = 0;
+                    let _request_id = get_u32(&body, &mut pos);
+                    if ty == SSH_FXP_DATA {
+                        let data = get_bytes(&body, &mut pos);
+                        let n = data.len() as u64;
+                        contents.extend_from_slice(&data);
+                        Ok(futures::future::Loop::Continue((
+                            channel,
+                            handle,
+                            offset + n,
+                            contents,
+                        )))
+                    } else {
+                        body.drain(..4);
+                        let mut status_pos = 0;
+                        let code = get_u32(&body, &mut status_pos);
+                        if code == SSH_FX_EOF {
+                            Ok(futures::future::Loop::Break((channel, handle, contents)))
+                        } else {
+                            let message = get_bytes(&body, &mut status_pos);
+                            Err(format_err!(
+                                "sftp read failed with status {}: {}",
+                                code,
+                                String::from_utf8_lossy(&message)
+                            ))
+                        }
+                    }
+                })
+            },
+        ))
+    }
+
+    pub fn close(
+        channel: RawChannel,
+        handle: Handle,
+    ) -> Box<Future<Item = RawChannel, Error = Error> + Send> {
+        let mut body = Vec::new();
+        put_u32(&mut body, 0); // request id
+        put_bytes(&mut body, &handle.0);
+        Box::new(
+            roundtrip(channel, SSH_FXP_CLOSE, body).and_then(|(channel, ty, mut body)| {
+                if ty != SSH_FXP_STATUS {
+                    return Err(format_err!(
+                        "expected sftp SSH_FXP_STATUS, got packet type {}",
+                        ty
+                    ));
+                }
+                body.drain(..4);
+                check_status("close", body)?;
+                Ok(channel)
+            }),
+        )
     }
 }